@@ -21,17 +21,65 @@ pub enum Error {
     Api(u16, String),
     Cdn(u16, String),
     Status(String),
+    /// Logging in was refused
+    Auth(String),
+    /// Download ended early, holds expected and received byte counts
+    Incomplete(u64, u64),
+    /// Stopped on request, e.g. Ctrl-C
+    Cancelled,
     Reqwest(reqwest::Error),
+    Io(std::io::Error),
     Unknown,
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Api(code, message) => write!(f, "API error {code}: {message}"),
+            Error::Cdn(code, message) => write!(f, "CDN error {code}: {message}"),
+            Error::Status(message) => f.write_str(message),
+            Error::Auth(message) => write!(f, "Login failed: {message}"),
+            Error::Incomplete(expected, received) => {
+                write!(f, "Incomplete download, got {received} of {expected} bytes")
+            }
+            Error::Cancelled => f.write_str("Cancelled"),
+            Error::Reqwest(err) => write!(f, "Request failed: {err}"),
+            Error::Io(err) => write!(f, "I/O error: {err}"),
+            Error::Unknown => f.write_str("Unknown error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Reqwest(err) => Some(err),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 impl From<reqwest::Error> for Error {
     fn from(value: reqwest::Error) -> Self {
         Self::Reqwest(value)
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
 impl Error {
+    /**
+     * Whether the credentials or the token were rejected.
+     */
+    pub fn is_auth(&self) -> bool {
+        matches!(self, Error::Auth(_) | Error::Api(401 | 403, _))
+    }
+
     pub async fn ensure_ok(response: reqwest::Response) -> Result<reqwest::Response> {
         let status = response.status();
         if !status.is_success() {
@@ -16,13 +16,23 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::{api::Result, client::Client, common::Sort};
+use std::collections::HashSet;
+
+use crate::{
+    api::{Error, Result},
+    client::Client,
+    common::{deserialize_flag, title_key, ContentType, LibraryStatus, Sort},
+    library::FileInfo,
+};
 
 use const_format::concatcp;
 
 const BASE_URL: &str = "https://api.nextory.se/api/app/catalogue/7.5/";
 const GROUPS_URL: &str = concatcp!(BASE_URL, "groups");
 const BOOKSFORBOOKGROUP_URL: &str = concatcp!(BASE_URL, "booksforbookgroup");
+const BOOKSFORAUTHOR_URL: &str = concatcp!(BASE_URL, "booksforauthor");
+const SEARCH_URL: &str = concatcp!(BASE_URL, "search");
+const SEARCH_AUTHORS_URL: &str = concatcp!(BASE_URL, "search/authors");
 
 use chrono::Datelike;
 
@@ -41,9 +51,27 @@ pub struct Book {
     pub authors: Box<[String]>,
     pub pubdate: crate::common::DateTime,
     pub esalesticket: String,
-    pub isupcoming: Option<u32>,
+    #[serde(default)]
+    pub isbn: String,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub isupcoming: bool,
     pub avgrate: f32,
-    pub libstatus: String,
+    #[serde(default)]
+    pub libstatus: LibraryStatus,
+    #[serde(rename = "type", default)]
+    pub contenttype: ContentType,
+    #[serde(default)]
+    pub file: Option<FileInfo>,
+}
+
+impl Book {
+    /**
+     * The content type of the file if the search tells it, falling back to the
+     * `type` field.
+     */
+    pub fn content_type(&self) -> ContentType {
+        FileInfo::content_type(self.file.as_ref()).or(self.contenttype)
+    }
 }
 
 impl std::fmt::Display for Book {
@@ -58,6 +86,18 @@ impl std::fmt::Display for Book {
     }
 }
 
+#[derive(serde::Deserialize, Debug)]
+pub struct Authors {
+    pub authors: Box<[Author]>,
+    pub authorcount: usize,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct Author {
+    pub id: u32,
+    pub name: String,
+}
+
 #[derive(serde::Deserialize, Debug)]
 pub struct Groups {
     pub bookgroups: Box<[Group]>,
@@ -67,6 +107,38 @@ pub struct Groups {
 #[derive(serde::Deserialize, Debug)]
 pub struct Group {
     pub id: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(rename = "type", default)]
+    pub grouptype: String,
+    #[serde(default)]
+    pub bookcount: usize,
+    #[serde(rename = "bookgroups", default)]
+    pub children: Box<[Group]>,
+}
+
+impl Group {
+    /**
+     * Depth first search through this group and its children, matching either the id
+     * or the (case insensitive) title.
+     */
+    pub fn find(&self, name: &str) -> Option<&Group> {
+        if self.id == name || self.title.eq_ignore_ascii_case(name) {
+            return Some(self);
+        }
+
+        self.children.iter().find_map(|child| child.find(name))
+    }
+}
+
+impl std::fmt::Display for Group {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{} [{}]", self.title, self.id))?;
+        if !self.grouptype.is_empty() {
+            f.write_fmt(format_args!(" {}", self.grouptype))?;
+        }
+        f.write_fmt(format_args!(" ({} books)", self.bookcount))
+    }
 }
 
 pub async fn groups(
@@ -75,7 +147,6 @@ pub async fn groups(
     view: Option<&str>,
 ) -> Result<Groups> {
     let mut request = client
-        .client
         .get(GROUPS_URL)
         .query(&[
             ("languages", "de,en"),
@@ -98,7 +169,7 @@ pub async fn booksforbookgroup(
     pagetoken: Option<&str>,
     pagenumber: Option<u32>,
 ) -> Result<Search> {
-    let mut request = client.client.get(BOOKSFORBOOKGROUP_URL).query(&[
+    let mut request = client.get(BOOKSFORBOOKGROUP_URL).query(&[
         ("bookgroupid", bookgroupid),
         ("sort", sort.into()),
         ("type", "0"),
@@ -118,7 +189,6 @@ pub async fn booksforbookgroup(
 
 pub async fn new(client: &Client, pagenumber: u32) -> Result<Search> {
     let request = client
-        .client
         .get(BOOKSFORBOOKGROUP_URL)
         .query(&[
             ("bookgroupid", "tttl_dynamic_1544$$ver_179"),
@@ -136,3 +206,251 @@ pub async fn new(client: &Client, pagenumber: u32) -> Result<Search> {
 
     client.request_with_auth(request).await
 }
+
+pub async fn search(client: &Client, query: &str, pagenumber: u32) -> Result<Search> {
+    let request = client
+        .get(SEARCH_URL)
+        .query(&[
+            ("q", query),
+            ("type", "0"),
+            ("languages", "de,en"),
+            ("rows", "12"),
+            ("includenotallowedbooks", "true"),
+        ])
+        .query(&[("pagenumber", pagenumber)]);
+
+    client.request_with_auth(request).await
+}
+
+pub async fn search_authors(client: &Client, query: &str, pagenumber: u32) -> Result<Authors> {
+    let request = client
+        .get(SEARCH_AUTHORS_URL)
+        .query(&[("q", query), ("languages", "de,en"), ("rows", "12")])
+        .query(&[("pagenumber", pagenumber)]);
+
+    client.request_with_auth(request).await
+}
+
+pub async fn booksforauthor(
+    client: &Client,
+    authorid: u32,
+    sort: Sort,
+    pagenumber: u32,
+) -> Result<Search> {
+    let request = client
+        .get(BOOKSFORAUTHOR_URL)
+        .query(&[("authorid", authorid)])
+        .query(&[
+            ("sort", sort.into()),
+            ("type", "0"),
+            ("languages", "de,en"),
+            ("rows", "12"),
+            ("includenotallowedbooks", "true"),
+        ])
+        .query(&[("pagenumber", pagenumber)]);
+
+    client.request_with_auth(request).await
+}
+
+/**
+ * Fetches every page of groups for the given view.
+ */
+pub async fn all_groups(client: &Client, view: Option<&str>) -> Result<Vec<Group>> {
+    let mut groups = Vec::new();
+    for i in 0.. {
+        let page = self::groups(client, i, view).await?;
+
+        if page.bookgroups.is_empty() {
+            break;
+        }
+
+        groups.extend(page.bookgroups.into_vec());
+    }
+
+    Ok(groups)
+}
+
+/**
+ * Resolves a group by id or title. Anything containing the "$$" version separator
+ * is taken to be an id as is. Titles are looked up in the default groups first and
+ * then in each of the given views.
+ */
+pub async fn find_group(
+    client: &Client,
+    name: &str,
+    views: &[impl AsRef<str>],
+) -> Result<String> {
+    if name.contains("$$") {
+        return Ok(name.to_owned());
+    }
+
+    let views = std::iter::once(None).chain(views.iter().map(|view| Some(view.as_ref())));
+    for view in views {
+        let groups = all_groups(client, view).await?;
+        if let Some(group) = groups.iter().find_map(|group| group.find(name)) {
+            return Ok(group.id.clone());
+        }
+    }
+
+    Err(Error::Status(format!("Couldn't find group \"{name}\"")))
+}
+
+/**
+ * Accepts either a numeric author id or a name. Names are looked up through the
+ * author search and have to match exactly (case insensitive), otherwise the error
+ * lists the authors that were found instead.
+ */
+pub async fn find_author(client: &Client, author: &str) -> Result<Author> {
+    if let Ok(id) = author.parse::<u32>() {
+        return Ok(Author {
+            id,
+            name: author.to_owned(),
+        });
+    }
+
+    let authors = search_authors(client, author, 0).await?;
+    let mut authors = authors.authors.into_vec();
+    let exact = authors
+        .iter()
+        .position(|a| a.name.eq_ignore_ascii_case(author.trim()));
+
+    match exact {
+        Some(idx) => Ok(authors.swap_remove(idx)),
+        None if !authors.is_empty() => {
+            let candidates = authors
+                .iter()
+                .map(|a| format!("{} ({})", a.name, a.id))
+                .collect::<Vec<_>>()
+                .join(", ");
+            Err(Error::Status(format!(
+                "Couldn't find author \"{author}\", did you mean: {candidates}"
+            )))
+        }
+        None => Err(Error::Status(format!("Couldn't find author \"{author}\""))),
+    }
+}
+
+/**
+ * Collects every page of an author's books. The same work shows up once per edition,
+ * only the first one of each content type is kept.
+ */
+pub async fn author_books(client: &Client, authorid: u32, sort: Sort) -> Result<Search> {
+    let mut ids = HashSet::new();
+    let mut titles = HashSet::new();
+    let mut books = Vec::new();
+    for i in 0.. {
+        let search = booksforauthor(client, authorid, sort, i).await?;
+
+        if search.books.is_empty() {
+            break;
+        }
+
+        for book in search.books.into_vec() {
+            let title = title_key(&book.title);
+            if ids.insert(book.id) && titles.insert((title, book.content_type())) {
+                books.push(book);
+            }
+        }
+    }
+
+    Ok(Search {
+        bookcount: books.len(),
+        books: books.into_boxed_slice(),
+        pagetoken: None,
+    })
+}
+
+/**
+ * Checks the check digit of an ISBN-10 or ISBN-13 and returns it as ISBN-13 without
+ * hyphens, so both forms of the same book compare equal.
+ */
+pub fn normalize_isbn(isbn: &str) -> Option<String> {
+    let isbn = isbn.replace('-', "").to_ascii_uppercase();
+    let bytes = isbn.as_bytes();
+
+    match bytes.len() {
+        10 => {
+            let mut sum = 0;
+            for (i, byte) in bytes.iter().enumerate() {
+                let digit = match byte {
+                    b'0'..=b'9' => u32::from(byte - b'0'),
+                    b'X' if i == 9 => 10,
+                    _ => return None,
+                };
+                sum += digit * (10 - i as u32);
+            }
+            if sum % 11 != 0 {
+                return None;
+            }
+
+            let isbn13 = format!("978{}", &isbn[..9]);
+            let check = (10 - isbn13_sum(&isbn13) % 10) % 10;
+            Some(format!("{isbn13}{check}"))
+        }
+        13 if bytes.iter().all(u8::is_ascii_digit) && isbn13_sum(&isbn) % 10 == 0 => {
+            Some(isbn)
+        }
+        _ => None,
+    }
+}
+
+fn isbn13_sum(digits: &str) -> u32 {
+    digits
+        .bytes()
+        .enumerate()
+        .map(|(i, byte)| u32::from(byte - b'0') * if i % 2 == 0 { 1 } else { 3 })
+        .sum()
+}
+
+/**
+ * Looks up the book id for an ISBN (10 or 13 digits, hyphens allowed) through the
+ * search. Only a hit with that exact ISBN counts, the search itself is free text.
+ */
+pub async fn find_isbn(client: &Client, isbn: &str) -> Result<u32> {
+    let Some(isbn) = normalize_isbn(isbn) else {
+        return Err(Error::Status(format!("Invalid ISBN {isbn}")));
+    };
+    let search = search(client, &isbn, 0).await?;
+
+    let found = search
+        .books
+        .iter()
+        .find(|book| normalize_isbn(&book.isbn).as_deref() == Some(isbn.as_str()));
+    match found {
+        Some(book) => Ok(book.id),
+        None => Err(Error::Status(format!("Couldn't find ISBN {isbn}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_isbn;
+
+    #[test]
+    fn isbn10_becomes_isbn13() {
+        assert_eq!(
+            normalize_isbn("0-306-40615-2").as_deref(),
+            Some("9780306406157")
+        );
+        assert_eq!(
+            normalize_isbn("080442957X").as_deref(),
+            Some("9780804429573")
+        );
+    }
+
+    #[test]
+    fn isbn13_is_kept() {
+        assert_eq!(
+            normalize_isbn("978-0-306-40615-7").as_deref(),
+            Some("9780306406157")
+        );
+    }
+
+    #[test]
+    fn invalid_check_digits_are_rejected() {
+        assert_eq!(normalize_isbn("0306406153"), None);
+        assert_eq!(normalize_isbn("9780306406158"), None);
+        /* A book id that happens to have 10 digits */
+        assert_eq!(normalize_isbn("1234567890"), None);
+    }
+}
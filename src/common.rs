@@ -21,14 +21,189 @@ pub struct EmptyResponse {}
 
 pub type DateTime = chrono::DateTime<chrono::Utc>;
 
-#[derive(Clone, Copy)]
+/* The API isn't consistent about types, flags show up as numbers, strings or bools */
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Raw {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+/**
+ * Accepts `true`/`false`, `0`/`1`, their string forms and `null` (as false).
+ */
+pub fn deserialize_flag<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<bool, D::Error> {
+    use serde::Deserialize;
+
+    Ok(match Option::<Raw>::deserialize(deserializer)? {
+        None => false,
+        Some(Raw::Bool(value)) => value,
+        Some(Raw::Int(value)) => value != 0,
+        Some(Raw::Str(value)) => matches!(value.trim(), "1" | "true" | "TRUE" | "True"),
+    })
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum LibraryStatus {
+    #[default]
+    NotInLibrary,
+    Active,
+    Inactive,
+    Completed,
+    Other(String),
+}
+
+impl From<&str> for LibraryStatus {
+    fn from(value: &str) -> Self {
+        match value {
+            "NOTINLIB" => Self::NotInLibrary,
+            "ACTIVE" => Self::Active,
+            "INACTIVE" => Self::Inactive,
+            "COMPLETED" => Self::Completed,
+            other => Self::Other(other.to_owned()),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for LibraryStatus {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match Option::<Raw>::deserialize(deserializer)? {
+            None => Self::NotInLibrary,
+            Some(Raw::Str(value)) => Self::from(value.to_ascii_uppercase().as_str()),
+            Some(Raw::Int(value)) => Self::Other(value.to_string()),
+            Some(Raw::Bool(value)) => Self::Other(value.to_string()),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ContentType {
+    Audio,
+    Ebook,
+    #[default]
+    Unknown,
+    Other(i64),
+}
+
+impl ContentType {
+    /**
+     * `self`, or `other` if this one is unknown.
+     */
+    pub fn or(self, other: ContentType) -> ContentType {
+        match self {
+            Self::Unknown => other,
+            known => known,
+        }
+    }
+}
+
+/**
+ * Which content types to download when a title exists as both audiobook and ebook.
+ * Titles that only exist in the other content type and books of unknown type are
+ * always accepted. The content type comes from the file format where a list tells
+ * it, see `library::FileFormat::content_type`, so lists without it aren't filtered.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum FormatPreference {
+    Audio,
+    Ebook,
+    #[default]
+    Both,
+}
+
+impl FormatPreference {
+    pub fn preferred(self) -> Option<ContentType> {
+        match self {
+            Self::Audio => Some(ContentType::Audio),
+            Self::Ebook => Some(ContentType::Ebook),
+            Self::Both => None,
+        }
+    }
+
+    /**
+     * `preferred_exists` tells whether the same title is available in the preferred
+     * content type, see [`title_key`].
+     */
+    pub fn accepts(self, contenttype: ContentType, preferred_exists: bool) -> bool {
+        match (self, contenttype) {
+            (Self::Audio, ContentType::Ebook) | (Self::Ebook, ContentType::Audio) => {
+                !preferred_exists
+            }
+            _ => true,
+        }
+    }
+}
+
+/**
+ * Writes to `{path}.tmp` first and renames it over `path`, so a run that is killed
+ * halfway through never leaves a truncated file behind.
+ */
+pub fn write_atomic(path: &std::path::Path, content: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+
+    let mut file = std::fs::File::create(&temp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&temp, path)
+}
+
+/**
+ * The title in lower case with whitespace collapsed, the editions of a work share it.
+ */
+pub fn title_key(title: &str) -> String {
+    title
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+impl<'de> serde::Deserialize<'de> for ContentType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        /*
+         * What the numeric values mean isn't documented and no captured response
+         * confirms a mapping, so they are kept as Other. The file format tells the
+         * content type reliably, see `FileFormat::content_type`.
+         */
+        Ok(match Option::<Raw>::deserialize(deserializer)? {
+            Some(Raw::Int(value)) => Self::Other(value),
+            Some(Raw::Str(value)) => match value.to_ascii_lowercase().as_str() {
+                "audio" | "audiobook" => Self::Audio,
+                "ebook" | "book" => Self::Ebook,
+                other => other.parse::<i64>().map_or(Self::Unknown, Self::Other),
+            },
+            Some(Raw::Bool(_)) | None => Self::Unknown,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
 pub enum Sort {
+    #[default]
+    #[serde(rename = "relevance")]
     Relevance,
+    #[serde(rename = "published_date", alias = "published-date")]
     PublishedDate,
+    #[serde(rename = "average_rating", alias = "rating")]
     Rating,
+    #[serde(rename = "title")]
     Title,
+    #[serde(rename = "authors")]
     Authors,
+    #[serde(rename = "volume")]
     Volume,
+    #[serde(rename = "NEST", alias = "nest")]
     Nest,
 }
 
@@ -45,3 +220,105 @@ impl From<Sort> for &str {
         }
     }
 }
+
+impl std::fmt::Display for Sort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str((*self).into())
+    }
+}
+
+impl std::str::FromStr for Sort {
+    type Err = String;
+
+    /**
+     * Accepts the API names (e.g. "published_date") as well as the CLI spelling
+     * (e.g. "published-date"), ignoring case.
+     */
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('-', "_").as_str() {
+            "relevance" => Ok(Self::Relevance),
+            "published_date" => Ok(Self::PublishedDate),
+            "rating" | "average_rating" => Ok(Self::Rating),
+            "title" => Ok(Self::Title),
+            "authors" => Ok(Self::Authors),
+            "volume" => Ok(Self::Volume),
+            "nest" => Ok(Self::Nest),
+            _ => Err(format!("unknown sort order \"{s}\"")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(serde::Deserialize)]
+    struct Flag {
+        #[serde(default, deserialize_with = "deserialize_flag")]
+        flag: bool,
+    }
+
+    fn flag(json: &str) -> bool {
+        serde_json::from_str::<Flag>(json).unwrap().flag
+    }
+
+    #[test]
+    fn flags() {
+        assert!(flag(r#"{"flag": true}"#));
+        assert!(!flag(r#"{"flag": false}"#));
+        assert!(flag(r#"{"flag": 1}"#));
+        assert!(!flag(r#"{"flag": 0}"#));
+        assert!(flag(r#"{"flag": "1"}"#));
+        assert!(flag(r#"{"flag": "true"}"#));
+        assert!(!flag(r#"{"flag": "0"}"#));
+        assert!(!flag(r#"{"flag": "maybe"}"#));
+        assert!(!flag(r#"{"flag": null}"#));
+        assert!(!flag(r#"{}"#));
+    }
+
+    fn status(json: &str) -> LibraryStatus {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn library_status() {
+        assert_eq!(status(r#""ACTIVE""#), LibraryStatus::Active);
+        assert_eq!(status(r#""inactive""#), LibraryStatus::Inactive);
+        assert_eq!(status(r#""COMPLETED""#), LibraryStatus::Completed);
+        assert_eq!(status(r#""NOTINLIB""#), LibraryStatus::NotInLibrary);
+        assert_eq!(status("null"), LibraryStatus::NotInLibrary);
+        assert_eq!(
+            status(r#""ARCHIVED""#),
+            LibraryStatus::Other("ARCHIVED".into())
+        );
+        assert_eq!(status("3"), LibraryStatus::Other("3".into()));
+        assert_eq!(status("true"), LibraryStatus::Other("true".into()));
+    }
+
+    fn contenttype(json: &str) -> ContentType {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn content_type() {
+        assert_eq!(contenttype("1"), ContentType::Other(1));
+        assert_eq!(contenttype("2"), ContentType::Other(2));
+        assert_eq!(contenttype(r#""1""#), ContentType::Other(1));
+        assert_eq!(contenttype(r#""Audiobook""#), ContentType::Audio);
+        assert_eq!(contenttype(r#""ebook""#), ContentType::Ebook);
+        assert_eq!(contenttype("7"), ContentType::Other(7));
+        assert_eq!(contenttype(r#""podcast""#), ContentType::Unknown);
+        assert_eq!(contenttype("false"), ContentType::Unknown);
+        assert_eq!(contenttype("null"), ContentType::Unknown);
+    }
+
+    #[test]
+    fn preference_falls_back() {
+        let audio = FormatPreference::Audio;
+        assert!(audio.accepts(ContentType::Audio, true));
+        assert!(!audio.accepts(ContentType::Ebook, true));
+        assert!(audio.accepts(ContentType::Ebook, false));
+        assert!(audio.accepts(ContentType::Unknown, true));
+        assert!(FormatPreference::Both.accepts(ContentType::Ebook, true));
+    }
+}
@@ -0,0 +1,136 @@
+/**
+ * Nextory Client
+ * Copyright (C) 2023 Luis
+ * 
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ * 
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::path::{Path, PathBuf};
+
+use nextory::{
+    api::{Error, Result},
+    common::{FormatPreference, Sort},
+    downloader::DEFAULT_PATH_TEMPLATE,
+    library::{FileFormat, Visibility},
+};
+
+/**
+ * CLI configuration file. Every value can be overridden by the matching flag.
+ */
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub account: Option<String>,
+    pub username: Option<String>,
+    pub password_command: Option<String>,
+    pub token_dir: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub device_id: Option<String>,
+    pub app_version: Option<String>,
+    pub locale: Option<String>,
+    pub proxy: Option<String>,
+    pub ca_cert: Option<PathBuf>,
+    pub sync: SyncConfig,
+}
+
+/**
+ * Options of `sync` and `retry-failed`. There is no concurrency setting, books are
+ * downloaded one after another.
+ */
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncConfig {
+    pub output: Option<PathBuf>,
+    pub path_template: String,
+    pub mark_completed: bool,
+    pub completed_visibility: Visibility,
+    pub return_after_download: bool,
+    pub prefer: FormatPreference,
+    pub formats: Vec<FileFormat>,
+    pub skip_upcoming: bool,
+    pub skip_active: bool,
+    pub skip_inactive: bool,
+    pub skip_new: bool,
+    pub categories: Vec<String>,
+    pub views: Vec<String>,
+    pub authors: Vec<String>,
+    pub sort: Sort,
+    pub max_attempts: u32,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            output: None,
+            path_template: DEFAULT_PATH_TEMPLATE.to_owned(),
+            mark_completed: false,
+            completed_visibility: Visibility::Private,
+            return_after_download: false,
+            prefer: FormatPreference::Both,
+            formats: Vec::new(),
+            skip_upcoming: false,
+            skip_active: false,
+            skip_inactive: false,
+            skip_new: false,
+            categories: Vec::new(),
+            views: Vec::new(),
+            authors: Vec::new(),
+            sort: Sort::Relevance,
+            max_attempts: 3,
+        }
+    }
+}
+
+impl Config {
+    /**
+     * `$XDG_CONFIG_HOME/nextory/config.toml`
+     */
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("nextory").join("config.toml"))
+    }
+
+    /**
+     * An explicitly given file has to exist, the default one is optional.
+     */
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_owned(), true),
+            None => match Self::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if !required && err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            Err(err) => {
+                return Err(Error::Status(format!(
+                    "Couldn't read config {}: {err}",
+                    path.display()
+                )))
+            }
+        };
+
+        toml::from_str(&content)
+            .map_err(|err| Error::Status(format!("Invalid config {}: {err}", path.display())))
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self)
+            .map_err(|err| Error::Status(format!("Couldn't serialize config: {err}")))
+    }
+}
@@ -15,149 +15,652 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-use std::{fs::create_dir_all, path::PathBuf};
+use std::{
+    collections::HashSet,
+    fs::create_dir_all,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::Datelike;
 
 use futures_util::stream::StreamExt;
 use id3::TagLike;
 use tokio::io::AsyncWriteExt;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    api::Result,
+    api::{Error, Result},
     catalogue,
     catalogue::Search,
     client::Client,
-    common::Sort,
+    common::{title_key, ContentType, FormatPreference, LibraryStatus, Sort},
+    events::{DownloadObserver, Event, SkipReason, Stage, Summary},
+    failures::FailedQueue,
     library,
-    library::{Book, File, FileFormat},
+    library::{Book, File, FileFormat, Visibility},
+    watchlist::{Upcoming, Watchlist},
 };
 
-const PROGRESS_TEMPLATE: &str = "{wide_bar} [{bytes:10}/{total_bytes:10}] {eta:4}";
+pub const DEFAULT_PATH_TEMPLATE: &str = "{authors}/{title}";
 
 pub struct Downloader {
     path: PathBuf,
-    pub style: indicatif::ProgressStyle,
-    mark_completed: bool,
+    path_template: String,
+    mark_completed: Option<Visibility>,
+    return_books: bool,
+    /* Free active slots, fetched on the first activation */
+    slots: Mutex<Option<usize>>,
+    watchlist: Option<Mutex<Watchlist>>,
+    failed: Option<Mutex<FailedQueue>>,
+    preference: FormatPreference,
+    formats: Vec<FileFormat>,
+    observers: Vec<Box<dyn DownloadObserver>>,
+    summary: Mutex<Summary>,
+    started: std::time::Instant,
+    cancel: CancellationToken,
 }
 
 impl Downloader {
-    pub fn new(path: PathBuf, mark_completed: bool) -> Self {
-        let style = indicatif::ProgressStyle::default_bar()
-            .template(PROGRESS_TEMPLATE)
-            .unwrap();
-
+    /**
+     * `mark_completed` adds every downloaded book to the completed list with the given
+     * visibility.
+     */
+    pub fn new(path: PathBuf, mark_completed: Option<Visibility>) -> Self {
         Self {
             path,
-            style,
+            path_template: DEFAULT_PATH_TEMPLATE.to_owned(),
             mark_completed,
+            return_books: false,
+            slots: Mutex::new(None),
+            watchlist: None,
+            failed: None,
+            preference: FormatPreference::Both,
+            formats: Vec::new(),
+            observers: Vec::new(),
+            summary: Mutex::new(Summary::default()),
+            started: std::time::Instant::now(),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /**
+     * Reports every [`Event`] of the run to `observer`, in addition to those added
+     * before. Closures taking an `&Event` work as observers too.
+     */
+    pub fn observer(mut self, observer: impl DownloadObserver + 'static) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    /**
+     * Stop once `cancel` is cancelled. The current file is aborted and removed, every
+     * method then returns [`Error::Cancelled`]. State like the watchlist is saved as it
+     * changes, so nothing is lost.
+     */
+    pub fn cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
+        Ok(())
+    }
+
+    fn emit(&self, event: Event) {
+        self.summary.lock().unwrap().count(&event);
+        for observer in &self.observers {
+            observer.on_event(&event);
+        }
+    }
+
+    /**
+     * Totals of everything downloaded so far.
+     */
+    pub fn summary(&self) -> Summary {
+        let mut summary = self.summary.lock().unwrap().clone();
+        summary.elapsed_secs = self.started.elapsed().as_secs();
+        summary
+    }
+
+    /**
+     * Sends the [`Event::Summary`] of the run and returns it.
+     */
+    pub fn finish(&self) -> Summary {
+        let summary = self.summary();
+        self.emit(Event::Summary(summary.clone()));
+        summary
+    }
+
+    /**
+     * Prefer books of one content type where a list holds both editions of a title, and
+     * only download files in one of `formats`. An empty list allows every format that
+     * can be stored as a single file.
+     */
+    pub fn formats(mut self, preference: FormatPreference, formats: Vec<FileFormat>) -> Self {
+        self.preference = preference;
+        self.formats = formats;
+        self
+    }
+
+    fn wants(&self, book: &Book) -> bool {
+        self.wants_format(Some(book.file.formatid))
+    }
+
+    /*
+     * Formats that can't be stored as a single file are never wanted. Lists of books
+     * that aren't active may not tell the format, those are let through.
+     */
+    fn wants_format(&self, formatid: Option<u32>) -> bool {
+        match formatid {
+            Some(formatid) => {
+                let format = FileFormat::from(formatid);
+                format.get_extension().is_some()
+                    && (self.formats.is_empty() || self.formats.contains(&format))
+            }
+            None => true,
+        }
+    }
+
+    /* Titles of a list that are available in the preferred content type */
+    fn preferred_titles<'a>(
+        &self,
+        books: impl Iterator<Item = (&'a str, ContentType)>,
+    ) -> HashSet<String> {
+        let Some(preferred) = self.preference.preferred() else {
+            return HashSet::new();
+        };
+
+        books
+            .filter(|(_, contenttype)| *contenttype == preferred)
+            .map(|(title, _)| title_key(title))
+            .collect()
+    }
+
+    fn prefers(&self, preferred: &HashSet<String>, title: &str, contenttype: ContentType) -> bool {
+        self.preference
+            .accepts(contenttype, preferred.contains(&title_key(title)))
+    }
+
+    /**
+     * Where to store books inside the output folder, '/' separates folders. Known
+     * placeholders are {title}, {authors}, {author}, {id}, {isbn} and {year}, the file
+     * extension is added automatically.
+     */
+    pub fn path_template(mut self, path_template: String) -> Self {
+        self.path_template = path_template;
+        self
+    }
+
+    /**
+     * Remember upcoming books instead of silently skipping them, see
+     * [`Downloader::download_upcoming`].
+     */
+    pub fn watchlist(mut self, watchlist: Watchlist) -> Self {
+        self.watchlist = Some(Mutex::new(watchlist));
+        self
+    }
+
+    /**
+     * Remember failed books for [`Downloader::retry_failed`] and forget them once they
+     * were downloaded.
+     */
+    pub fn failed(mut self, failed: FailedQueue) -> Self {
+        self.failed = Some(Mutex::new(failed));
+        self
+    }
+
+    fn report(&self, id: u32, stage: Stage, err: &Error) {
+        tracing::error!(bookid = id, error = %err, "{stage} failed");
+        self.emit(Event::Failed {
+            id,
+            stage,
+            error: err.to_string(),
+        });
+    }
+
+    fn fail(&self, id: u32, stage: Stage, err: &Error, esalesticket: &str) -> Result<()> {
+        self.report(id, stage, err);
+
+        let Some(failed) = &self.failed else {
+            return Ok(());
+        };
+
+        let mut failed = failed.lock().unwrap();
+        if failed.record(id, stage, err.to_string(), esalesticket) {
+            tracing::warn!(bookid = id, "Giving up, too many failed attempts");
+        }
+        failed.save()
+    }
+
+    /* Books that failed too often aren't activated again, see FailedQueue */
+    fn given_up(&self, id: u32) -> bool {
+        self.failed
+            .as_ref()
+            .is_some_and(|failed| failed.lock().unwrap().is_dead(id))
+    }
+
+    fn succeeded(&self, id: u32) -> Result<()> {
+        let Some(failed) = &self.failed else {
+            return Ok(());
+        };
+
+        let mut failed = failed.lock().unwrap();
+        if failed.remove(id) {
+            failed.save()?;
+        }
+
+        Ok(())
+    }
+
+    fn watch(&self, id: u32, book: Upcoming) -> Result<()> {
+        let Some(watchlist) = &self.watchlist else {
+            return Ok(());
+        };
+
+        let mut watchlist = watchlist.lock().unwrap();
+        if watchlist.insert(id, book) {
+            watchlist.save()?;
         }
+
+        Ok(())
+    }
+
+    /**
+     * Return every book with [`library::directctbookdeletion`] once it was downloaded
+     * completely, freeing its active slot for the next one.
+     */
+    pub fn return_books(mut self, return_books: bool) -> Self {
+        self.return_books = return_books;
+        self
+    }
+
+    /* False when every slot is taken, the free count is only fetched once per run */
+    async fn reserve_slot(&self, client: &Client) -> Result<bool> {
+        let known = *self.slots.lock().unwrap();
+        let free = match known {
+            Some(free) => free,
+            None => {
+                let active = library::list_active(client).await?;
+                active.maxactivecount.saturating_sub(active.bookcount)
+            }
+        };
+
+        if free == 0 {
+            *self.slots.lock().unwrap() = Some(0);
+            return Ok(false);
+        }
+
+        *self.slots.lock().unwrap() = Some(free - 1);
+
+        Ok(true)
+    }
+
+    fn release_slot(&self) {
+        if let Some(free) = self.slots.lock().unwrap().as_mut() {
+            *free += 1;
+        }
+    }
+
+    async fn return_book(&self, client: &Client, bookid: u32) -> Result<()> {
+        library::directctbookdeletion(client, bookid).await?;
+        self.release_slot();
+
+        Ok(())
+    }
+
+    /**
+     * Activates a book in a free slot and downloads it. Books that are already active
+     * don't take up another slot. Books that find no free slot, failed activations and
+     * failed downloads are reported and skipped.
+     */
+    async fn activate_and_download(
+        &self,
+        client: &Client,
+        bookid: u32,
+        esalesticket: &str,
+        traceid: &str,
+        active: bool,
+    ) -> Result<()> {
+        self.check_cancelled()?;
+
+        if self.given_up(bookid) {
+            tracing::info!(bookid, "Failed too often before. Skipping!");
+            self.skip(bookid, SkipReason::GaveUp);
+            return Ok(());
+        }
+
+        if !active && !self.reserve_slot(client).await? {
+            tracing::warn!(bookid, "No free active slot. Skipping!");
+            self.skip(bookid, SkipReason::NoSlot);
+            return Ok(());
+        }
+
+        let activation =
+            match library::directctbookactivation(client, bookid, esalesticket, traceid).await {
+                Ok(activation) => activation,
+                Err(err) => {
+                    if !active {
+                        self.release_slot();
+                    }
+                    return self.fail(bookid, Stage::Activation, &err, esalesticket);
+                }
+            };
+        self.emit(Event::Activation { id: bookid });
+
+        self.download_activated(client, bookid, &activation.books, esalesticket)
+            .await
+    }
+
+    async fn download_activated(
+        &self,
+        client: &Client,
+        bookid: u32,
+        book: &Book,
+        esalesticket: &str,
+    ) -> Result<()> {
+        if !self.wants(book) {
+            tracing::info!(bookid, "{book} isn't in a wanted format. Skipping!");
+            self.skip(bookid, SkipReason::Format);
+            if self.return_books {
+                if let Err(err) = self.return_book(client, bookid).await {
+                    return self.fail(bookid, Stage::Return, &err, esalesticket);
+                }
+            }
+
+            return Ok(());
+        }
+
+        if !self.download_book(client, book, esalesticket).await? {
+            return Ok(());
+        }
+
+        self.finish_book(client, bookid, esalesticket).await
+    }
+
+    /**
+     * Marks a downloaded book completed and returns it, as configured. Failures are
+     * reported and queued like failed downloads, the run carries on.
+     */
+    async fn finish_book(&self, client: &Client, bookid: u32, esalesticket: &str) -> Result<()> {
+        if let Some(visibility) = self.mark_completed {
+            if let Err(err) = library::add_completed(client, bookid, visibility, None).await {
+                return self.fail(bookid, Stage::Completion, &err, esalesticket);
+            }
+        }
+
+        if self.return_books {
+            if let Err(err) = self.return_book(client, bookid).await {
+                return self.fail(bookid, Stage::Return, &err, esalesticket);
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Fills in the path template for a book, relative to the output folder. Values
+     * never contain '/', so only the template itself creates folders.
+     */
+    fn book_path(&self, book: &Book, extension: &str) -> PathBuf {
+        let title = match book.title.char_indices().nth(200) {
+            None => &book.title,
+            Some((idx, _)) => &book.title[..idx],
+        };
+        let authors = if book.authors.len() > 5 {
+            &book.authors[..5]
+        } else {
+            &book.authors
+        };
+
+        let values = [
+            ("{title}", title.to_owned()),
+            ("{authors}", authors.join(" & ")),
+            ("{author}", authors.first().cloned().unwrap_or_default()),
+            ("{id}", book.id.to_string()),
+            ("{isbn}", book.isbn.clone()),
+            ("{year}", book.pubdate.year().to_string()),
+        ];
+
+        let mut components: Vec<String> = self
+            .path_template
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .map(|component| {
+                values
+                    .iter()
+                    .fold(component.to_owned(), |component, (key, value)| {
+                        component.replace(key, &value.replace('/', "_"))
+                    })
+            })
+            .collect();
+
+        /* Not set_extension, titles may well contain dots */
+        match components.last_mut() {
+            Some(file_name) => *file_name = format!("{file_name}.{extension}"),
+            None => components.push(format!("{}.{extension}", book.id)),
+        }
+
+        components.into_iter().collect()
+    }
+
+    fn skip(&self, id: u32, reason: SkipReason) {
+        self.emit(Event::Skipped { id, reason });
+    }
+
+    fn discover(&self, id: u32, title: &str) {
+        self.emit(Event::BookDiscovered {
+            id,
+            title: title.to_owned(),
+        });
     }
 
     async fn download_file(
         &self,
         client: &Client,
-        folder: &str,
+        bookid: u32,
+        relative: &Path,
         api_file: &File,
-        file_name: &str,
     ) -> Result<PathBuf> {
-        let mut path = self.path.clone();
+        let path = self.path.join(relative);
 
-        path.push(folder.replace('/', "_"));
-        if !path.exists() {
-            create_dir_all(&path).unwrap();
+        if let Some(parent) = path.parent() {
+            if !parent.exists() {
+                create_dir_all(parent)?;
+            }
         }
 
-        path.push(file_name.replace('/', "_"));
-
         if path.exists() {
-            println!("{file_name} exists. Skipping!");
+            tracing::info!("{} exists. Skipping!", relative.display());
+            self.skip(bookid, SkipReason::Exists);
             return Ok(path);
         }
 
-        println!("downloading {path:?}");
+        tracing::info!(path = %path.display(), "Downloading");
 
-        let mut file = tokio::fs::File::create(&path).await.unwrap();
+        /* Only complete files get the real name, anything else would be skipped next time */
+        let mut part = path.clone().into_os_string();
+        part.push(".part");
+        let part = PathBuf::from(part);
 
         let response = client.start_download(api_file).await?;
+        let mut file = tokio::fs::File::create(&part).await?;
 
-        let content_size = if let Some(length) = response
+        let content_length: Option<u64> = response
             .headers()
             .get("Content-Length")
             .and_then(|e| e.to_str().ok())
-            .and_then(|s| s.parse().ok())
-        {
-            length
-        } else {
-            api_file.sizeinbytes
-        } as u64;
+            .and_then(|s| s.parse().ok());
+        let content_size = content_length.unwrap_or(api_file.sizeinbytes as u64);
 
-        let bar = indicatif::ProgressBar::new(content_size).with_style(self.style.clone());
+        self.emit(Event::DownloadStarted {
+            id: bookid,
+            path: path.clone(),
+            size: content_size,
+        });
 
+        let mut received = 0;
+        let mut reported = 0;
         let mut stream = response.bytes_stream();
-        while let Some(Ok(mut chunk)) = stream.next().await {
-            bar.inc(chunk.len() as u64);
-            let _ = file.write_all_buf(&mut chunk).await;
+        let result = async {
+            loop {
+                let chunk = tokio::select! {
+                    chunk = stream.next() => chunk,
+                    _ = self.cancel.cancelled() => return Err(Error::Cancelled),
+                };
+                let Some(mut chunk) = chunk.transpose()? else {
+                    break;
+                };
+
+                received += chunk.len() as u64;
+                file.write_all_buf(&mut chunk).await?;
+                for observer in &self.observers {
+                    observer.on_progress(bookid, received, content_size);
+                }
+
+                /* In steps of 5% */
+                let step = (received * 20).checked_div(content_size).unwrap_or(0);
+                if step > reported {
+                    reported = step;
+                    self.emit(Event::DownloadProgress {
+                        id: bookid,
+                        received,
+                        total: content_size,
+                    });
+                }
+            }
+
+            if let Some(expected) = content_length {
+                if expected != received {
+                    return Err(Error::Incomplete(expected, received));
+                }
+            }
+
+            file.flush().await?;
+            Ok(())
         }
+        .await;
 
-        bar.finish_and_clear();
+        drop(file);
+
+        if let Err(err) = result {
+            let _ = tokio::fs::remove_file(&part).await;
+            return Err(err);
+        }
+        tokio::fs::rename(&part, &path).await?;
+
+        self.emit(Event::DownloadFinished {
+            id: bookid,
+            path: path.clone(),
+            bytes: received,
+        });
 
         Ok(path)
     }
 
-    pub async fn download_book(&self, client: &Client, book: &Book) -> Result<PathBuf> {
+    /**
+     * Downloads and tags an activated book. Failures are reported and queued, only
+     * errors saving the queue are returned. Returns true on success.
+     */
+    #[tracing::instrument(skip_all, fields(book = book.id))]
+    async fn download_book(
+        &self,
+        client: &Client,
+        book: &Book,
+        esalesticket: &str,
+    ) -> Result<bool> {
+        self.check_cancelled()?;
+
         let file_format = FileFormat::from(book.file.formatid);
-        let file_name = match book.title.char_indices().nth(200) {
-            None => &book.title,
-            Some((idx, _)) => &book.title[..idx],
+        let Some(extension) = file_format.get_extension() else {
+            tracing::warn!("{book} is in an unsupported format ({file_format:?}). Skipping!");
+            self.skip(book.id, SkipReason::Format);
+            return Ok(false);
         };
-
-        let file_name = format!("{}.{}", file_name, file_format.get_extension());
-        let authors = if book.authors.len() > 5 {
-            &book.authors[..5]
-        } else {
-            &book.authors
+        let relative = self.book_path(book, extension);
+        let path = match self
+            .download_file(client, book.id, &relative, &book.file)
+            .await
+        {
+            Ok(path) => path,
+            Err(Error::Cancelled) => return Err(Error::Cancelled),
+            Err(err) => {
+                self.fail(book.id, Stage::Download, &err, esalesticket)?;
+                return Ok(false);
+            }
         };
-        let folder = authors.join(" & ");
-        let path = self
-            .download_file(client, &folder, &book.file, &file_name)
-            .await?;
 
         if file_format == FileFormat::Mp3 {
-            use id3::{
-                frame::{Picture, PictureType},
-                Tag, Version,
-            };
+            if let Err(err) = Self::tag(client, &path, book).await {
+                self.fail(book.id, Stage::Tagging, &err, esalesticket)?;
+                return Ok(false);
+            }
+        }
 
-            let mut tag = Tag::read_from_path(&path).unwrap();
+        self.succeeded(book.id)?;
 
-            tag.set_title(&book.title);
-            if let Some(author) = book.authors.get(0) {
-                tag.set_artist(author);
-            }
+        Ok(true)
+    }
+
+    async fn tag(client: &Client, path: &Path, book: &Book) -> Result<()> {
+        use id3::{
+            frame::{Picture, PictureType},
+            Tag, Version,
+        };
 
-            let response = reqwest::get(&book.imageurl).await?;
-            let mime = response.headers().get("content-type").unwrap();
+        let tag_error = |err: id3::Error| Error::Status(format!("Couldn't tag {path:?}: {err}"));
 
+        let mut tag = Tag::read_from_path(path).map_err(tag_error)?;
+
+        tag.set_title(&book.title);
+        if let Some(author) = book.authors.first() {
+            tag.set_artist(author);
+        }
+
+        let response = client.fetch_image(&book.imageurl).await?;
+        let mime = response
+            .headers()
+            .get("content-type")
+            .and_then(|mime| mime.to_str().ok())
+            .unwrap_or("image/jpeg")
+            .to_owned();
+
+        /* Replayed recordings don't hold images */
+        let data = response.bytes().await?.to_vec();
+        if !data.is_empty() {
             tag.add_frame(Picture {
-                mime_type: mime.to_str().unwrap().to_owned(),
+                mime_type: mime,
                 picture_type: PictureType::CoverFront,
                 description: String::new(),
-                data: response.bytes().await.unwrap().to_vec(),
+                data,
             });
-
-            tag.write_to_path(&path, Version::Id3v23).unwrap();
         }
 
-        Ok(path)
+        tag.write_to_path(path, Version::Id3v23).map_err(tag_error)
     }
 
     pub async fn download_active(&self, client: &Client) -> Result<()> {
-        println!("Downloading \"active\" books");
-        let active = library::list_active(&client).await?;
+        tracing::info!("Downloading \"active\" books");
+        let active = library::list_active(client).await?;
+        let preferred = self.preferred_titles(
+            active
+                .books
+                .iter()
+                .map(|book| (book.title.as_str(), book.content_type())),
+        );
         for book in active.books.iter() {
-            self.download_book(&client, &book).await?;
+            self.discover(book.id, &book.title);
+            if !self.prefers(&preferred, &book.title, book.content_type()) || !self.wants(book) {
+                self.skip(book.id, SkipReason::Format);
+                continue;
+            }
 
-            if self.mark_completed {
-                library::add_completed(&client, book.id).await?;
+            if self.download_book(client, book, "").await? {
+                self.finish_book(client, book.id, "").await?;
             }
         }
 
@@ -165,34 +668,53 @@ impl Downloader {
     }
 
     pub async fn download_inactive(&self, client: &Client) -> Result<()> {
-        println!("Downloading \"inactive\"/saved books");
-        loop {
-            let inactive = library::list_inactive(&client, 0).await?;
-
-            for book in inactive.books.iter() {
-                /* Upcoming books can't be activated */
-                if book.isupcoming == 1 {
-                    continue;
-                }
+        tracing::info!("Downloading \"inactive\"/saved books");
 
-                match library::directctbookactivation(&client, book.id, "", "").await {
-                    Ok(activation) => {
-                        if let Err(err) = self.download_book(&client, &activation.books).await {
-                            eprintln!("{} failed with {:?}", book.id, err);
-                        }
+        /* Activated books drop out of the list, so collect everything before activating */
+        let mut books = Vec::new();
+        for i in 0.. {
+            self.check_cancelled()?;
+            let inactive = library::list_inactive(client, i).await?;
+            let last = inactive.books.len() < 12;
+            books.extend(inactive.books.into_vec());
+            if last {
+                break;
+            }
+        }
 
-                        if self.mark_completed {
-                            library::add_completed(&client, book.id).await?;
-                        }
-                    }
-                    Err(err) => {
-                        eprintln!("Failed to download with error {:?}", err);
-                    }
-                }
+        let preferred = self.preferred_titles(
+            books
+                .iter()
+                .map(|book| (book.title.as_str(), book.content_type())),
+        );
+        for book in books.iter() {
+            self.discover(book.id, &book.title);
+            /* Don't waste a slot on a book in the wrong format */
+            let formatid = book.file.as_ref().map(|file| file.formatid);
+            if !self.prefers(&preferred, &book.title, book.content_type())
+                || !self.wants_format(formatid)
+            {
+                self.skip(book.id, SkipReason::Format);
+                continue;
             }
-            if inactive.books.len() < 12 {
-                break;
+
+            /* Upcoming books can't be activated */
+            if book.isupcoming {
+                self.watch(
+                    book.id,
+                    Upcoming {
+                        title: book.title.clone(),
+                        authors: book.authors.clone(),
+                        pubdate: book.pubdate,
+                        esalesticket: String::new(),
+                    },
+                )?;
+                self.skip(book.id, SkipReason::Upcoming);
+                continue;
             }
+
+            self.activate_and_download(client, book.id, "", "", false)
+                .await?;
         }
 
         Ok(())
@@ -206,15 +728,16 @@ impl Downloader {
     ) -> Result<()> {
         let mut i: u32 = 0;
         loop {
-            println!("Categories page {i}");
-            let groups = catalogue::groups(&client, i, view).await?;
+            self.check_cancelled()?;
+            tracing::debug!(page = i, "Categories page");
+            let groups = catalogue::groups(client, i, view).await?;
 
             if groups.bookgroups.is_empty() {
                 break;
             }
 
             for group in groups.bookgroups.iter() {
-                self.download_category(&group.id, sort, &client).await?;
+                self.download_category(&group.id, sort, client).await?;
             }
             i += 1;
         }
@@ -224,32 +747,157 @@ impl Downloader {
 
     pub async fn download_search(&self, client: &Client, search: &Search) -> Result<()> {
         let traceid = client.random.next_string::<21>();
+        let preferred = self.preferred_titles(
+            search
+                .books
+                .iter()
+                .map(|book| (book.title.as_str(), book.content_type())),
+        );
 
         for book in search.books.iter() {
-            println!("{book}");
-            if book.libstatus != "NOTINLIB" && book.isupcoming.is_some_and(|v| v == 1) {
+            tracing::info!("{book}");
+            self.discover(book.id, &book.title);
+            let formatid = book.file.as_ref().map(|file| file.formatid);
+            if !self.prefers(&preferred, &book.title, book.content_type())
+                || !self.wants_format(formatid)
+            {
+                self.skip(book.id, SkipReason::Format);
                 continue;
             }
 
-            match library::directctbookactivation(
-                &client,
+            /* Upcoming books can't be activated */
+            if book.isupcoming {
+                self.watch(
+                    book.id,
+                    Upcoming {
+                        title: book.title.clone(),
+                        authors: book.authors.clone(),
+                        pubdate: Some(book.pubdate),
+                        esalesticket: book.esalesticket.clone(),
+                    },
+                )?;
+                self.skip(book.id, SkipReason::Upcoming);
+                continue;
+            }
+
+            let active = book.libstatus == LibraryStatus::Active;
+            self.activate_and_download(
+                client,
                 book.id,
                 &book.esalesticket,
                 traceid.as_str(),
+                active,
             )
-            .await
-            {
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Retries every watched book whose publication date has passed. Books that can be
+     * activated now are downloaded and dropped from the watchlist. Without a free slot
+     * the remaining books are left for the next run. Books the API refuses stay watched,
+     * network and server errors are reported as failures and a rejected token is
+     * returned.
+     */
+    pub async fn download_upcoming(&self, client: &Client) -> Result<()> {
+        let Some(watchlist) = &self.watchlist else {
+            return Ok(());
+        };
+
+        let due = watchlist.lock().unwrap().due(chrono::Utc::now());
+        if due.is_empty() {
+            return Ok(());
+        }
+
+        tracing::info!("Checking {} upcoming books", due.len());
+        let traceid = client.random.next_string::<21>();
+        let mut released = Vec::new();
+        for (id, book) in due {
+            self.check_cancelled()?;
+            /* The book stays on the watchlist for the next run */
+            if !self.reserve_slot(client).await? {
+                tracing::warn!("No free active slot for {book}, trying again next run");
+                break;
+            }
+
+            let activation =
+                library::directctbookactivation(client, id, &book.esalesticket, traceid.as_str());
+            match activation.await {
                 Ok(activation) => {
-                    if let Err(err) = self.download_book(&client, &activation.books).await {
-                        println!("{} failed with {:?}", book.id, err);
+                    self.emit(Event::Activation { id });
+                    {
+                        let mut watchlist = watchlist.lock().unwrap();
+                        watchlist.remove(id);
+                        watchlist.save()?;
                     }
 
-                    if self.mark_completed {
-                        library::add_completed(client, book.id).await?;
-                    }
+                    self.download_activated(client, id, &activation.books, &book.esalesticket)
+                        .await?;
+                    released.push(book);
                 }
                 Err(err) => {
-                    println!("Failed to download with error {:?}", err);
+                    self.release_slot();
+                    match err {
+                        err if err.is_auth() => return Err(err),
+                        /* The book stays on the watchlist either way */
+                        Error::Api(500..=599, _) | Error::Reqwest(_) | Error::Io(_) => {
+                            self.report(id, Stage::Activation, &err)
+                        }
+                        err => {
+                            tracing::info!(bookid = id, error = %err, "{book} isn't available yet")
+                        }
+                    }
+                }
+            }
+        }
+
+        for book in released {
+            tracing::info!("Newly released: {book}");
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Retries every book of the failed queue. Books that are still active are downloaded
+     * right away, the others are activated again.
+     */
+    pub async fn retry_failed(&self, client: &Client) -> Result<()> {
+        let Some(failed) = &self.failed else {
+            return Ok(());
+        };
+
+        let books = {
+            let failed = failed.lock().unwrap();
+            for (id, dead) in failed.dead() {
+                tracing::info!(bookid = id, "Not retrying anymore, {dead}");
+            }
+            failed.books()
+        };
+        if books.is_empty() {
+            return Ok(());
+        }
+
+        tracing::info!("Retrying {} failed books", books.len());
+        let active = library::list_active(client).await?;
+        let traceid = client.random.next_string::<21>();
+        for (id, book) in books {
+            match active.books.iter().find(|active| active.id == id) {
+                Some(active) => {
+                    self.download_activated(client, id, active, &book.esalesticket)
+                        .await?
+                }
+                None => {
+                    self.activate_and_download(
+                        client,
+                        id,
+                        &book.esalesticket,
+                        traceid.as_str(),
+                        false,
+                    )
+                    .await?
                 }
             }
         }
@@ -257,20 +905,34 @@ impl Downloader {
         Ok(())
     }
 
+    /*
+     * Listings are collected completely before downloading, so --prefer sees the
+     * editions of a title even when they are on different pages.
+     */
+    fn collected(books: Vec<catalogue::Book>) -> Search {
+        Search {
+            bookcount: books.len(),
+            books: books.into_boxed_slice(),
+            pagetoken: None,
+        }
+    }
+
     pub async fn download_new(&self, client: &Client) -> Result<()> {
-        println!("Downloading new books");
+        tracing::info!("Downloading new books");
+        let mut books = Vec::new();
         for i in 0.. {
+            self.check_cancelled()?;
             let search = catalogue::new(client, i).await?;
 
-            println!("page {i}; count: {}", search.bookcount);
-            if search.books.len() == 0 {
+            tracing::debug!(page = i, count = search.bookcount, "Fetched page");
+            if search.books.is_empty() {
                 break;
             }
 
-            self.download_search(client, &search).await?;
+            books.extend(search.books.into_vec());
         }
 
-        Ok(())
+        self.download_search(client, &Self::collected(books)).await
     }
 
     pub async fn download_category(
@@ -279,27 +941,39 @@ impl Downloader {
         sort: Sort,
         client: &Client,
     ) -> Result<()> {
-        println!("Downloading category {category}");
+        tracing::info!("Downloading category {category}");
+        let mut books = Vec::new();
         let mut pagetoken: Option<String> = None;
         let mut i = 0;
         loop {
+            self.check_cancelled()?;
             let search = if let Some(p) = pagetoken {
-                catalogue::booksforbookgroup(&client, category, sort, Some(&p), Some(i)).await?
+                catalogue::booksforbookgroup(client, category, sort, Some(&p), Some(i)).await?
             } else {
-                catalogue::booksforbookgroup(&client, category, sort, None, Some(i)).await?
+                catalogue::booksforbookgroup(client, category, sort, None, Some(i)).await?
             };
 
-            println!("page {i}; count: {}", search.bookcount);
-            if search.books.len() == 0 {
+            tracing::debug!(page = i, count = search.bookcount, "Fetched page");
+            if search.books.is_empty() {
                 break;
             }
 
-            self.download_search(client, &search).await?;
+            books.extend(search.books.into_vec());
 
             pagetoken = search.pagetoken;
             i += 1;
         }
 
-        Ok(())
+        self.download_search(client, &Self::collected(books)).await
+    }
+
+    pub async fn download_author(&self, author: &str, sort: Sort, client: &Client) -> Result<()> {
+        let author = catalogue::find_author(client, author).await?;
+        tracing::info!("Downloading author {} ({})", author.name, author.id);
+
+        let search = catalogue::author_books(client, author.id, sort).await?;
+        tracing::debug!(count = search.bookcount, "Author books");
+
+        self.download_search(client, &search).await
     }
 }
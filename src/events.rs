@@ -0,0 +1,240 @@
+/**
+ * Nextory Client
+ * Copyright (C) 2023 Luis
+ * 
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ * 
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::path::PathBuf;
+
+/**
+ * Receives the progress of a [`crate::downloader::Downloader`] run, e.g. to update a
+ * GUI. Called from the downloading task, so implementations should return quickly.
+ */
+pub trait DownloadObserver: Send + Sync {
+    fn on_event(&self, event: &Event);
+
+    /**
+     * Called for every chunk of a file, between [`Event::DownloadStarted`] and
+     * [`Event::DownloadFinished`] or [`Event::Failed`]. Meant for progress bars, the
+     * [`Event::DownloadProgress`] events are much coarser.
+     */
+    fn on_progress(&self, _id: u32, _received: u64, _total: u64) {}
+}
+
+impl<F: Fn(&Event) + Send + Sync> DownloadObserver for F {
+    fn on_event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/**
+ * Progress of a [`crate::downloader::Downloader`] run. Serializes to one JSON object
+ * with an `event` field naming the variant.
+ */
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    BookDiscovered {
+        id: u32,
+        title: String,
+    },
+    Activation {
+        id: u32,
+    },
+    DownloadStarted {
+        id: u32,
+        path: PathBuf,
+        size: u64,
+    },
+    /// Sent for every 5% of a file
+    DownloadProgress {
+        id: u32,
+        received: u64,
+        total: u64,
+    },
+    DownloadFinished {
+        id: u32,
+        path: PathBuf,
+        bytes: u64,
+    },
+    Skipped {
+        id: u32,
+        reason: SkipReason,
+    },
+    Failed {
+        id: u32,
+        stage: Stage,
+        error: String,
+    },
+    Summary(Summary),
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The file is already in the output folder
+    Exists,
+    /// Not the preferred content type or file format
+    Format,
+    /// Not released yet, see [`crate::watchlist::Watchlist`]
+    Upcoming,
+    /// Every active slot is taken
+    NoSlot,
+    /// Failed too often before, see [`crate::failures::FailedQueue`]
+    GaveUp,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Activation,
+    Download,
+    /// Writing the ID3 tags of an mp3
+    Tagging,
+    /// Adding the downloaded book to the completed list
+    Completion,
+    /// Returning the book to free its active slot
+    Return,
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Activation => f.write_str("activation"),
+            Self::Download => f.write_str("download"),
+            Self::Tagging => f.write_str("tagging"),
+            Self::Completion => f.write_str("completion"),
+            Self::Return => f.write_str("return"),
+        }
+    }
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct Failure {
+    pub id: u32,
+    pub stage: Stage,
+    pub error: String,
+}
+
+/**
+ * Totals of a run, counted from its events.
+ */
+#[derive(serde::Serialize, Clone, Debug, Default)]
+pub struct Summary {
+    pub discovered: usize,
+    pub activated: usize,
+    pub downloaded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub failures: Vec<Failure>,
+    pub bytes: u64,
+    pub elapsed_secs: u64,
+}
+
+impl Summary {
+    pub fn count(&mut self, event: &Event) {
+        match event {
+            Event::BookDiscovered { .. } => self.discovered += 1,
+            Event::Activation { .. } => self.activated += 1,
+            Event::DownloadFinished { bytes, .. } => {
+                self.downloaded += 1;
+                self.bytes += bytes;
+            }
+            Event::Skipped { .. } => self.skipped += 1,
+            Event::Failed { id, stage, error } => {
+                self.failed += 1;
+                self.failures.push(Failure {
+                    id: *id,
+                    stage: *stage,
+                    error: error.clone(),
+                });
+            }
+            Event::DownloadStarted { .. } | Event::DownloadProgress { .. } | Event::Summary(_) => {}
+        }
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Discovered  {:>6}", self.discovered)?;
+        writeln!(f, "Activated   {:>6}", self.activated)?;
+        writeln!(
+            f,
+            "Downloaded  {:>6} ({})",
+            self.downloaded,
+            human_bytes(self.bytes)
+        )?;
+        writeln!(f, "Skipped     {:>6}", self.skipped)?;
+        writeln!(f, "Failed      {:>6}", self.failed)?;
+        writeln!(f, "Elapsed     {:>6}", duration(self.elapsed_secs))?;
+
+        for failure in &self.failures {
+            writeln!(
+                f,
+                "  {} failed at {}: {}",
+                failure.id, failure.stage, failure.error
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/**
+ * Formats a size in binary units, e.g. "1.50 MiB".
+ */
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.2} {}", UNITS[unit])
+    }
+}
+
+/**
+ * Formats seconds as "HH:MM:SS", prefixed with the days if there are any.
+ */
+fn duration(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let time = format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+
+    if days > 0 {
+        format!("{days}d {time}")
+    } else {
+        time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_bytes_and_duration() {
+        assert_eq!(human_bytes(512), "512 B");
+        assert_eq!(human_bytes(1536 * 1024), "1.50 MiB");
+        assert_eq!(duration(95), "00:01:35");
+        assert_eq!(duration(90061), "1d 01:01:01");
+    }
+}
@@ -0,0 +1,176 @@
+/**
+ * Nextory Client
+ * Copyright (C) 2023 Luis
+ * 
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ * 
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use crate::{
+    api::{Error, Result},
+    common::{write_atomic, DateTime},
+    events::Stage,
+};
+
+/**
+ * Books that failed to download, persisted as JSON so `retry-failed` can process just
+ * those. Books that failed `max_attempts` times are moved to the dead letter list and
+ * not retried anymore.
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug)]
+pub struct FailedQueue {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    max_attempts: u32,
+    books: BTreeMap<u32, Failed>,
+    #[serde(default)]
+    dead: BTreeMap<u32, Failed>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Failed {
+    pub stage: Stage,
+    pub error: String,
+    pub attempts: u32,
+    pub timestamp: DateTime,
+    #[serde(default)]
+    pub esalesticket: String,
+}
+
+impl std::fmt::Display for Failed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} failed {} time(s), last on {}: {}",
+            self.stage,
+            self.attempts,
+            self.timestamp.date_naive(),
+            self.error
+        ))
+    }
+}
+
+impl FailedQueue {
+    /**
+     * Loads the queue from `path`, starting out empty if it doesn't exist yet.
+     */
+    pub fn load(path: PathBuf, max_attempts: u32) -> Result<Self> {
+        let mut queue: FailedQueue = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|err| Error::Status(format!("Invalid failed queue {path:?}: {err}")))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => FailedQueue::default(),
+            Err(err) => return Err(err.into()),
+        };
+        queue.path = path;
+        queue.max_attempts = max_attempts;
+
+        Ok(queue)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let content = serde_json::to_vec_pretty(self)
+            .map_err(|err| Error::Status(format!("Couldn't serialize failed queue: {err}")))?;
+        write_atomic(&self.path, &content)?;
+
+        Ok(())
+    }
+
+    /**
+     * Counts another failed attempt. Returns true if the book was given up on and moved
+     * to the dead letter list. Books that are already on it stay there.
+     */
+    pub fn record(&mut self, id: u32, stage: Stage, error: String, esalesticket: &str) -> bool {
+        let previous = self.dead.get(&id).or_else(|| self.books.get(&id));
+        let attempts = previous.map_or(0, |failed| failed.attempts) + 1;
+        let esalesticket = match previous {
+            Some(failed) if esalesticket.is_empty() => failed.esalesticket.clone(),
+            _ => esalesticket.to_owned(),
+        };
+
+        let failed = Failed {
+            stage,
+            error,
+            attempts,
+            timestamp: chrono::Utc::now(),
+            esalesticket,
+        };
+
+        if self.dead.contains_key(&id) {
+            self.dead.insert(id, failed);
+            false
+        } else if attempts >= self.max_attempts {
+            self.books.remove(&id);
+            self.dead.insert(id, failed);
+            true
+        } else {
+            self.books.insert(id, failed);
+            false
+        }
+    }
+
+    /**
+     * Forgets a book that succeeded after all. Returns true if it was queued or dead.
+     */
+    pub fn remove(&mut self, id: u32) -> bool {
+        self.books.remove(&id).is_some() | self.dead.remove(&id).is_some()
+    }
+
+    pub fn is_dead(&self, id: u32) -> bool {
+        self.dead.contains_key(&id)
+    }
+
+    pub fn books(&self) -> Vec<(u32, Failed)> {
+        self.books
+            .iter()
+            .map(|(&id, failed)| (id, failed.clone()))
+            .collect()
+    }
+
+    pub fn dead(&self) -> &BTreeMap<u32, Failed> {
+        &self.dead
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dead_books_stay_dead() {
+        let mut queue = FailedQueue {
+            max_attempts: 2,
+            ..FailedQueue::default()
+        };
+
+        assert!(!queue.record(1, Stage::Download, "first".into(), "ticket"));
+        assert_eq!(queue.books().len(), 1);
+        assert!(queue.record(1, Stage::Download, "second".into(), ""));
+        assert!(queue.books().is_empty());
+        assert!(queue.is_dead(1));
+
+        /* Failing again in a normal sync doesn't queue it for retry-failed */
+        assert!(!queue.record(1, Stage::Activation, "third".into(), ""));
+        assert!(queue.books().is_empty());
+        assert_eq!(queue.dead()[&1].attempts, 3);
+        assert_eq!(queue.dead()[&1].esalesticket, "ticket");
+
+        assert!(queue.remove(1));
+        assert!(!queue.is_dead(1));
+    }
+}
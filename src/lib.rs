@@ -21,4 +21,16 @@ pub mod client;
 pub mod common;
 pub mod library;
 pub mod randomstring;
+pub mod record;
+pub mod tokenstore;
+pub mod transport;
 pub mod catalogue;
+
+#[cfg(feature = "downloader")]
+pub mod downloader;
+#[cfg(feature = "downloader")]
+pub mod events;
+#[cfg(feature = "downloader")]
+pub mod failures;
+#[cfg(feature = "downloader")]
+pub mod watchlist;
@@ -16,16 +16,26 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::{api::Result, client::Client, common::EmptyResponse};
+use crate::{
+    api::Result,
+    client::Client,
+    common::{deserialize_flag, ContentType, DateTime, EmptyResponse},
+};
+
+use chrono::Datelike;
 
 use const_format::concatcp;
 
 const BASE_URL: &str = "https://api.nextory.se/api/app/library/7.5/";
 const ACTIVE_URL: &str = concatcp!(BASE_URL, "active");
 const INACTIVE_URL: &str = concatcp!(BASE_URL, "inactive");
+const INACTIVE_ADD_URL: &str = concatcp!(BASE_URL, "inactive/add");
+const INACTIVE_REMOVE_URL: &str = concatcp!(BASE_URL, "inactive/remove");
 const ACTIVATION_URL: &str = concatcp!(BASE_URL, "directctbookactivation");
 const DELETION_URL: &str = concatcp!(BASE_URL, "directctbookdeletion");
+const COMPLETED_URL: &str = concatcp!(BASE_URL, "completed");
 const COMPLETED_ADD_URL: &str = concatcp!(BASE_URL, "completed/add");
+const COMPLETED_REMOVE_URL: &str = concatcp!(BASE_URL, "completed/remove");
 
 pub async fn list_active(client: &Client) -> Result<Active> {
     client.get_with_auth(ACTIVE_URL).await
@@ -33,7 +43,6 @@ pub async fn list_active(client: &Client) -> Result<Active> {
 
 pub async fn list_inactive(client: &Client, pagenumber: u32) -> Result<Inactive> {
     let request = client
-        .client
         .get(INACTIVE_URL)
         .query(&[("type", "0"), ("sort", "dateModified"), ("rows", "12")])
         .query(&[("pagenumber", pagenumber)]);
@@ -41,6 +50,23 @@ pub async fn list_inactive(client: &Client, pagenumber: u32) -> Result<Inactive>
     client.request_with_auth(request).await
 }
 
+/**
+ * Adds a book to the saved/inactive list, see [`list_inactive`].
+ */
+pub async fn save(client: &Client, bookid: u32) -> Result<EmptyResponse> {
+    let request = client.post(INACTIVE_ADD_URL).query(&[("bookid", bookid)]);
+
+    client.request_with_auth(request).await
+}
+
+pub async fn unsave(client: &Client, bookid: u32) -> Result<EmptyResponse> {
+    let request = client
+        .post(INACTIVE_REMOVE_URL)
+        .query(&[("bookid", bookid)]);
+
+    client.request_with_auth(request).await
+}
+
 /**
  * traceid usually is a 21 Character alphanumeric string which is generated once per page. See [`crate::randomstring::RandomString`]
  */
@@ -51,7 +77,6 @@ pub async fn directctbookactivation(
     traceid: &str,
 ) -> Result<Activation> {
     let request = client
-        .client
         .post(ACTIVATION_URL)
         .query(&[("bookid", bookid)])
         .query(&[("esalesticket", esalesticket), ("traceid", traceid)]);
@@ -61,7 +86,6 @@ pub async fn directctbookactivation(
 
 pub async fn directctbookdeletion(client: &Client, bookid: u32) -> Result<EmptyResponse> {
     let request = client
-        .client
         .post(DELETION_URL)
         .query(&[("bookid", bookid)])
         .query(&[("esalesticket", "")]);
@@ -69,20 +93,65 @@ pub async fn directctbookdeletion(client: &Client, bookid: u32) -> Result<EmptyR
     client.request_with_auth(request).await
 }
 
-pub async fn add_completed(client: &Client, bookid: u32) -> Result<EmptyResponse> {
+/**
+ * Adds a book to the completed list. The completion date defaults to now.
+ */
+pub async fn add_completed(
+    client: &Client,
+    bookid: u32,
+    visibility: Visibility,
+    completion_date: Option<DateTime>,
+) -> Result<EmptyResponse> {
     const DATETIME_FORMAT_STRING: &str = "%Y-%m-%d %H:%M:%S %z";
 
-    let completion_date = chrono::offset::Utc::now();
+    let completion_date = completion_date.unwrap_or_else(chrono::offset::Utc::now);
     let formatted_date = completion_date.format(DATETIME_FORMAT_STRING).to_string();
     let request = client
-        .client
         .post(COMPLETED_ADD_URL)
         .query(&[("bookid", bookid)])
-        .query(&[("visibility", "PUBLIC"), ("completeddate", &formatted_date)]);
+        .query(&[
+            ("visibility", visibility.into()),
+            ("completeddate", formatted_date.as_str()),
+        ]);
+
+    client.request_with_auth(request).await
+}
+
+pub async fn list_completed(client: &Client, pagenumber: u32) -> Result<Completed> {
+    let request = client
+        .get(COMPLETED_URL)
+        .query(&[("rows", "12")])
+        .query(&[("pagenumber", pagenumber)]);
+
+    client.request_with_auth(request).await
+}
+
+pub async fn remove_completed(client: &Client, bookid: u32) -> Result<EmptyResponse> {
+    let request = client
+        .post(COMPLETED_REMOVE_URL)
+        .query(&[("bookid", bookid)]);
 
     client.request_with_auth(request).await
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    Public,
+    #[default]
+    Private,
+}
+
+impl From<Visibility> for &str {
+    fn from(value: Visibility) -> Self {
+        match value {
+            Visibility::Public => "PUBLIC",
+            Visibility::Private => "PRIVATE",
+        }
+    }
+}
+
 #[derive(serde::Deserialize, Debug)]
 pub struct Active {
     pub books: Box<[Book]>,
@@ -95,10 +164,65 @@ pub struct Inactive {
     pub books: Box<[InactiveBook]>,
 }
 
+#[derive(serde::Deserialize, Debug)]
+pub struct Completed {
+    pub books: Box<[CompletedBook]>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct CompletedBook {
+    pub id: u32,
+    pub title: String,
+    pub authors: Box<[String]>,
+}
+
+impl std::fmt::Display for CompletedBook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} {} by {}",
+            self.id,
+            self.title,
+            self.authors.join(", ")
+        ))
+    }
+}
+
 #[derive(serde::Deserialize, Debug)]
 pub struct InactiveBook {
     pub id: u32,
-    pub isupcoming: u8,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub isupcoming: bool,
+    #[serde(rename = "type", default)]
+    pub contenttype: ContentType,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub authors: Box<[String]>,
+    #[serde(default)]
+    pub pubdate: Option<DateTime>,
+    #[serde(default)]
+    pub file: Option<FileInfo>,
+}
+
+impl InactiveBook {
+    /**
+     * The content type of the file if the list tells it, falling back to the `type`
+     * field.
+     */
+    pub fn content_type(&self) -> ContentType {
+        FileInfo::content_type(self.file.as_ref()).or(self.contenttype)
+    }
+}
+
+impl std::fmt::Display for InactiveBook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} {} by {}",
+            self.id,
+            self.title,
+            self.authors.join(", ")
+        ))
+    }
 }
 
 #[derive(serde::Deserialize, Debug)]
@@ -110,14 +234,38 @@ pub struct Activation {
 pub struct Book {
     pub id: u32,
     pub isbn: String,
-    pub isupcoming: u8,
-    #[serde(rename = "type")]
-    _type: u8,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub isupcoming: bool,
+    #[serde(rename = "type", default)]
+    pub contenttype: ContentType,
     pub title: String,
     pub imageurl: String,
     pub authors: Box<[String]>,
     pub file: File,
-    pub pubdate: crate::common::DateTime,
+    pub pubdate: DateTime,
+}
+
+impl Book {
+    /**
+     * The content type of the file, falling back to the `type` field.
+     */
+    pub fn content_type(&self) -> ContentType {
+        FileFormat::from(self.file.formatid)
+            .content_type()
+            .or(self.contenttype)
+    }
+}
+
+impl std::fmt::Display for Book {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} {}({}) by {}",
+            self.id,
+            self.title,
+            self.pubdate.year(),
+            self.authors.join(", ")
+        ))
+    }
 }
 
 #[derive(serde::Deserialize, Debug)]
@@ -128,13 +276,36 @@ pub struct File {
     pub sizeinbytes: usize,
 }
 
-#[derive(PartialEq)]
+/**
+ * The part of [`File`] that lists of books not activated yet may carry.
+ */
+#[derive(serde::Deserialize, Debug)]
+pub struct FileInfo {
+    pub formatid: u32,
+}
+
+impl FileInfo {
+    pub fn content_type(file: Option<&FileInfo>) -> ContentType {
+        file.map_or(ContentType::Unknown, |file| {
+            FileFormat::from(file.formatid).content_type()
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
 pub enum FileFormat {
     Mp3,
+    #[serde(rename = "epub")]
+    #[cfg_attr(feature = "clap", value(name = "epub"))]
     EPub,
     PdfDrm,
     PdfWatermark,
+    #[serde(rename = "hls")]
+    #[cfg_attr(feature = "clap", value(skip))]
     HLS,
+    #[cfg_attr(feature = "clap", value(skip))]
     Unknown,
 }
 
@@ -152,12 +323,115 @@ impl From<u32> for FileFormat {
 }
 
 impl FileFormat {
-    pub fn get_extension(&self) -> &'static str {
+    /**
+     * `None` for formats that can't be stored as a single file.
+     */
+    pub fn get_extension(&self) -> Option<&'static str> {
         match self {
-            FileFormat::Mp3 => "mp3",
-            FileFormat::EPub => "epub",
-            FileFormat::PdfDrm | FileFormat::PdfWatermark => "pdf",
-            _ => panic!("unsupported file type for single file store"),
+            FileFormat::Mp3 => Some("mp3"),
+            FileFormat::EPub => Some("epub"),
+            FileFormat::PdfDrm | FileFormat::PdfWatermark => Some("pdf"),
+            FileFormat::HLS | FileFormat::Unknown => None,
         }
     }
+
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            FileFormat::Mp3 | FileFormat::HLS => ContentType::Audio,
+            FileFormat::EPub | FileFormat::PdfDrm | FileFormat::PdfWatermark => ContentType::Ebook,
+            FileFormat::Unknown => ContentType::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Shaped like a directctbookactivation response, trimmed to the parsed fields */
+    const ACTIVATION: &str = r#"{
+        "books": {
+            "id": 1234567,
+            "isbn": "9789100000000",
+            "isupcoming": 0,
+            "type": 1,
+            "title": "Boken",
+            "imageurl": "https://example.com/cover.jpg",
+            "authors": ["Författare"],
+            "file": {
+                "url": "https://example.com/book.epub",
+                "formatid": 9,
+                "duration": "",
+                "sizeinbytes": 1024
+            },
+            "pubdate": "2023-01-01T00:00:00Z"
+        }
+    }"#;
+
+    #[test]
+    fn content_type_follows_the_file_format() {
+        let activation: Activation = serde_json::from_str(ACTIVATION).unwrap();
+        let book = activation.books;
+        assert_eq!(book.contenttype, ContentType::Other(1));
+        assert_eq!(FileFormat::from(book.file.formatid), FileFormat::EPub);
+        assert_eq!(book.content_type(), ContentType::Ebook);
+    }
+
+    #[test]
+    fn numeric_type_is_not_guessed() {
+        let audio: InactiveBook =
+            serde_json::from_str(r#"{"id": 1, "type": 2, "file": {"formatid": 22}}"#).unwrap();
+        assert_eq!(audio.content_type(), ContentType::Audio);
+
+        let unknown: InactiveBook = serde_json::from_str(r#"{"id": 2, "type": 1}"#).unwrap();
+        assert_eq!(unknown.content_type(), ContentType::Other(1));
+
+        let named: InactiveBook =
+            serde_json::from_str(r#"{"id": 3, "type": "audiobook"}"#).unwrap();
+        assert_eq!(named.content_type(), ContentType::Audio);
+    }
+
+    /* Answers with a canned list of active books, checking the request on the way */
+    struct MockTransport;
+
+    impl crate::transport::Transport for MockTransport {
+        fn execute(
+            &self,
+            request: reqwest::Request,
+        ) -> crate::transport::BoxFuture<'_, Result<reqwest::Response>> {
+            assert_eq!(request.url().as_str(), ACTIVE_URL);
+            assert_eq!(request.headers()["token"], "token");
+            /* The device identity reaches any transport, not just reqwest's */
+            assert!(request.headers().contains_key("deviceid"));
+            assert_eq!(request.headers().get_all("user-agent").iter().count(), 1);
+
+            let activation: serde_json::Value = serde_json::from_str(ACTIVATION).unwrap();
+            let body = serde_json::json!({
+                "data": {
+                    "books": [activation["books"]],
+                    "bookcount": 1,
+                    "maxactivecount": 3
+                }
+            });
+            let response = http::Response::builder()
+                .status(200)
+                .body(body.to_string())
+                .unwrap();
+            Box::pin(async move { Ok(response.into()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn list_active_through_a_transport() {
+        let client = Client::builder()
+            .transport(Box::new(MockTransport))
+            .build("token".into())
+            .unwrap();
+
+        let active = list_active(&client).await.unwrap();
+        assert_eq!(active.bookcount, 1);
+        assert_eq!(active.maxactivecount, 3);
+        assert_eq!(active.books[0].id, 1234567);
+        assert_eq!(active.books[0].title, "Boken");
+    }
 }
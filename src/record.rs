@@ -0,0 +1,436 @@
+/**
+ * Nextory Client
+ * Copyright (C) 2023 Luis
+ * 
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ * 
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+
+use crate::{
+    api::{Error, Result},
+    transport::{BoxFuture, Transport},
+};
+
+/* Never written to a recording */
+const REDACTED_HEADERS: &[&str] = &["token", "authorization", "cookie", "proxy-authorization"];
+const REDACTED_FIELDS: &[&str] = &["token", "loginkey"];
+/* Logging in as a profile sends its loginkey and a checksum in the query */
+const REDACTED_QUERY: &[&str] = &["loginkey", "checksum"];
+const REDACTED: &str = "<redacted>";
+
+/* Random per request, ignored when looking up a recorded response */
+const IGNORED_QUERY: &[&str] = &["traceid"];
+
+/**
+ * One API request and its response, a line in a recording.
+ *
+ * Bodies are stored as text. Multipart bodies (the login form) and anything but JSON
+ * or text responses (file downloads) aren't recorded, replaying a download fails
+ * rather than producing an empty file.
+ */
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct Exchange {
+    pub method: String,
+    pub url: String,
+    pub request_headers: BTreeMap<String, String>,
+    pub request_body: Option<String>,
+    pub status: u16,
+    pub response_headers: BTreeMap<String, String>,
+    pub response_body: Option<String>,
+}
+
+impl Exchange {
+    pub(crate) fn new(request: &reqwest::Request) -> Self {
+        let request_body = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map(|body| String::from_utf8_lossy(body).into_owned());
+
+        Self {
+            method: request.method().to_string(),
+            url: redact_url(request.url()).to_string(),
+            request_headers: headers(request.headers()),
+            request_body,
+            status: 0,
+            response_headers: BTreeMap::new(),
+            response_body: None,
+        }
+    }
+
+    pub(crate) fn response(&mut self, response: &reqwest::Response) {
+        self.status = response.status().as_u16();
+        self.response_headers = headers(response.headers());
+        /* The recorded body is already decompressed */
+        self.response_headers.remove("content-length");
+        self.response_headers.remove("content-encoding");
+    }
+
+    pub(crate) fn body(&mut self, body: &[u8]) {
+        let body = String::from_utf8_lossy(body);
+        let body = match serde_json::from_str::<serde_json::Value>(&body) {
+            Ok(mut json) => {
+                redact(&mut json);
+                json.to_string()
+            }
+            Err(_) => body.into_owned(),
+        };
+        self.response_body = Some(body);
+    }
+
+    fn matches(&self, method: &str, url: &str) -> bool {
+        self.method == method
+            && reqwest::Url::parse(&self.url).is_ok_and(|recorded| key(&recorded) == url)
+    }
+
+    pub(crate) fn to_response(&self) -> Result<reqwest::Response> {
+        if self.response_body.is_none() && (200..300).contains(&self.status) {
+            return Err(Error::Status(format!(
+                "{} {} was recorded without its content",
+                self.method, self.url
+            )));
+        }
+
+        let mut builder = http::Response::builder().status(self.status);
+        for (name, value) in &self.response_headers {
+            builder = builder.header(name, value);
+        }
+
+        let body = self.response_body.clone().unwrap_or_default();
+        let response = builder
+            .body(body)
+            .map_err(|err| Error::Status(format!("Invalid recorded response: {err}")))?;
+
+        Ok(response.into())
+    }
+}
+
+fn is_text(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json") || value.starts_with("text/"))
+}
+
+fn headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+                REDACTED.to_owned()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+fn redact(json: &mut serde_json::Value) {
+    match json {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if REDACTED_FIELDS.contains(&key.as_str()) && value.is_string() {
+                    *value = REDACTED.into();
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn redact_url(url: &reqwest::Url) -> reqwest::Url {
+    let mut url = url.clone();
+    if url.query().is_none() {
+        return url;
+    }
+
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(name, value)| {
+            let value = if REDACTED_QUERY.contains(&name.as_ref()) {
+                REDACTED.to_owned()
+            } else {
+                value.into_owned()
+            };
+            (name.into_owned(), value)
+        })
+        .collect();
+    url.query_pairs_mut().clear().extend_pairs(query);
+
+    url
+}
+
+/*
+ * Url without the random query parameters. Redacted parameters match any value, the
+ * recording doesn't know them.
+ */
+fn key(url: &reqwest::Url) -> String {
+    let mut url = redact_url(url);
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| !IGNORED_QUERY.contains(&name.as_ref()))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(query);
+    }
+
+    url.to_string()
+}
+
+/**
+ * Transport that appends every exchange of the wrapped transport as one JSON line
+ * to a file.
+ */
+pub struct Recorder {
+    inner: Box<dyn Transport>,
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn new(inner: Box<dyn Transport>, path: &Path) -> Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            inner,
+            file: Mutex::new(file),
+        })
+    }
+
+    async fn record_exchange(&self, request: reqwest::Request) -> Result<reqwest::Response> {
+        let mut exchange = Exchange::new(&request);
+        let response = self.inner.execute(request).await?;
+        exchange.response(&response);
+
+        /* Only API responses are buffered, files are passed on as stream */
+        if !is_text(response.headers()) {
+            self.record(&exchange)?;
+            return Ok(response);
+        }
+
+        let mut builder = http::Response::builder().status(response.status());
+        for (name, value) in response.headers() {
+            if exchange.response_headers.contains_key(name.as_str()) {
+                builder = builder.header(name, value);
+            }
+        }
+
+        let body = response.bytes().await?;
+        exchange.body(&body);
+        self.record(&exchange)?;
+
+        let response = builder
+            .body(body)
+            .map_err(|err| Error::Status(format!("Invalid response: {err}")))?;
+
+        Ok(response.into())
+    }
+
+    pub fn record(&self, exchange: &Exchange) -> Result<()> {
+        let mut line = serde_json::to_string(exchange)
+            .map_err(|err| Error::Status(format!("Couldn't record request: {err}")))?;
+        line.push('\n');
+
+        self.file.lock().unwrap().write_all(line.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+/**
+ * Serves responses from a recording instead of the network. Every recorded exchange
+ * is served once, in the order it was recorded.
+ */
+pub struct Replay {
+    exchanges: Mutex<Vec<Exchange>>,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .map_err(|err| Error::Status(format!("Couldn't read {}: {err}", path.display())))?;
+
+        let mut exchanges = Vec::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let exchange = serde_json::from_str(&line).map_err(|err| {
+                Error::Status(format!("{}:{}: {err}", path.display(), number + 1))
+            })?;
+            exchanges.push(exchange);
+        }
+
+        Ok(Self {
+            exchanges: Mutex::new(exchanges),
+        })
+    }
+
+    fn respond(&self, request: &reqwest::Request) -> Result<reqwest::Response> {
+        let method = request.method().as_str();
+        let url = key(request.url());
+
+        let mut exchanges = self.exchanges.lock().unwrap();
+        let Some(index) = exchanges
+            .iter()
+            .position(|exchange| exchange.matches(method, &url))
+        else {
+            return Err(Error::Status(format!(
+                "No recorded response for {method} {url}"
+            )));
+        };
+
+        exchanges.remove(index).to_response()
+    }
+}
+
+impl Transport for Recorder {
+    fn execute(&self, request: reqwest::Request) -> BoxFuture<'_, Result<reqwest::Response>> {
+        Box::pin(self.record_exchange(request))
+    }
+}
+
+impl Transport for Replay {
+    fn execute(&self, request: reqwest::Request) -> BoxFuture<'_, Result<reqwest::Response>> {
+        let response = self.respond(&request);
+        Box::pin(async move { response })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACCOUNTS: &str =
+        r#"{"data":{"accounts":[{"name":"Kid","loginkey":"secret-key"}]},"token":"secret-token"}"#;
+
+    /* Answers every request with the same body */
+    struct Canned(&'static str, &'static str);
+
+    impl Transport for Canned {
+        fn execute(&self, _: reqwest::Request) -> BoxFuture<'_, Result<reqwest::Response>> {
+            let response = http::Response::builder()
+                .status(200)
+                .header(CONTENT_TYPE, self.1)
+                .body(self.0)
+                .unwrap();
+            Box::pin(async move { Ok(response.into()) })
+        }
+    }
+
+    fn request(url: &str) -> reqwest::Request {
+        reqwest::Client::new()
+            .get(url)
+            .header("token", "secret-token")
+            .build()
+            .unwrap()
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("nextory-{name}-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let mut exchange = Exchange::new(&request(
+            "https://example.com/login?loginkey=secret-key&checksum=ABCDEF&traceid=1",
+        ));
+        exchange.body(ACCOUNTS.as_bytes());
+
+        let line = serde_json::to_string(&exchange).unwrap();
+        assert!(!line.contains("secret"), "{line}");
+        assert!(!line.contains("ABCDEF"), "{line}");
+        assert!(exchange.url.contains("traceid=1"));
+        assert_eq!(exchange.request_headers["token"], REDACTED);
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let path = temp_path("record");
+        let recorder = Recorder::new(Box::new(Canned(ACCOUNTS, "application/json")), &path).unwrap();
+        let recorded = recorder
+            .execute(request("https://example.com/accounts?traceid=1"))
+            .await
+            .unwrap();
+        assert_eq!(recorded.text().await.unwrap(), ACCOUNTS);
+        recorder
+            .execute(request(
+                "https://example.com/login?loginkey=secret-key&checksum=AB",
+            ))
+            .await
+            .unwrap();
+
+        let replay = Replay::load(&path).unwrap();
+        /* The traceid differs and the loginkey is only known redacted */
+        let replayed = replay
+            .execute(request("https://example.com/accounts?traceid=2"))
+            .await
+            .unwrap();
+        let body: serde_json::Value = replayed.json().await.unwrap();
+        assert_eq!(body["data"]["accounts"][0]["name"], "Kid");
+        assert_eq!(body["data"]["accounts"][0]["loginkey"], REDACTED);
+        assert!(replay
+            .execute(request(
+                "https://example.com/login?loginkey=other&checksum=CD"
+            ))
+            .await
+            .is_ok());
+
+        /* Every exchange is served once */
+        assert!(replay
+            .execute(request("https://example.com/accounts"))
+            .await
+            .is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn downloads_are_not_replayed() {
+        let path = temp_path("download");
+        let recorder = Recorder::new(Box::new(Canned("mp3", "audio/mpeg")), &path).unwrap();
+        let recorded = recorder
+            .execute(request("https://example.com/book.mp3"))
+            .await
+            .unwrap();
+        assert_eq!(recorded.text().await.unwrap(), "mp3");
+
+        let replay = Replay::load(&path).unwrap();
+        assert!(replay
+            .execute(request("https://example.com/book.mp3"))
+            .await
+            .is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
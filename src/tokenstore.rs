@@ -0,0 +1,295 @@
+/**
+ * Nextory Client
+ * Copyright (C) 2023 Luis
+ * 
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ * 
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    fs,
+    io::{ErrorKind, Write},
+    path::PathBuf,
+};
+
+use crate::api::Result;
+
+#[cfg(feature = "encryption")]
+use crate::api::Error;
+
+/**
+ * Persists login tokens per account, so the credentials are only needed once.
+ */
+pub trait TokenStore {
+    fn load(&self, account: &str) -> Result<Option<String>>;
+    fn store(&self, account: &str, token: &str) -> Result<()>;
+    fn remove(&self, account: &str) -> Result<()>;
+
+    /**
+     * Like [`TokenStore::load`], but an entry that can't be read, e.g. one stored
+     * before encryption was turned on, is removed and reported as missing.
+     */
+    fn load_or_reset(&self, account: &str) -> Result<Option<String>> {
+        match self.load(account) {
+            Err(err) => {
+                tracing::warn!(account, error = %err, "Discarding unreadable entry");
+                self.remove(account)?;
+                Ok(None)
+            }
+            loaded => loaded,
+        }
+    }
+}
+
+/**
+ * Stores each token in its own file, readable only by the current user. Writes go
+ * through a temporary file and a rename, so an interrupted write never leaves a
+ * truncated token behind.
+ */
+pub struct FileTokenStore {
+    dir: PathBuf,
+}
+
+impl FileTokenStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /**
+     * `$XDG_STATE_HOME/nextory` (or the platform's config directory where there is no
+     * state directory).
+     */
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::state_dir()
+            .or_else(dirs::config_dir)
+            .map(|dir| dir.join("nextory"))
+    }
+
+    fn path(&self, account: &str) -> PathBuf {
+        let name: String = account
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '@' | '.' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+
+        self.dir.join(format!("{name}.token"))
+    }
+
+    fn create_dir(&self) -> Result<()> {
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+
+        Ok(builder.create(&self.dir)?)
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self, account: &str) -> Result<Option<String>> {
+        match fs::read_to_string(self.path(account)) {
+            Ok(token) => Ok(Some(token.trim().to_owned())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn store(&self, account: &str, token: &str) -> Result<()> {
+        self.create_dir()?;
+
+        let path = self.path(account);
+        let temp = path.with_extension("token.tmp");
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(&temp)?;
+        file.write_all(token.as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&temp, &path)?;
+
+        Ok(())
+    }
+
+    fn remove(&self, account: &str) -> Result<()> {
+        match fs::remove_file(self.path(account)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/**
+ * Wraps another store, encrypting tokens at rest with a key derived from a passphrase
+ * (Argon2id + ChaCha20-Poly1305).
+ */
+#[cfg(feature = "encryption")]
+pub struct EncryptedTokenStore<S: TokenStore> {
+    inner: S,
+    passphrase: String,
+}
+
+#[cfg(feature = "encryption")]
+impl<S: TokenStore> EncryptedTokenStore<S> {
+    const SALT_LENGTH: usize = 16;
+    const NONCE_LENGTH: usize = 12;
+
+    pub fn new(inner: S, passphrase: String) -> Self {
+        Self { inner, passphrase }
+    }
+
+    fn cipher(&self, salt: &[u8]) -> Result<chacha20poly1305::ChaCha20Poly1305> {
+        use chacha20poly1305::KeyInit;
+
+        let mut key = [0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(self.passphrase.as_bytes(), salt, &mut key)
+            .map_err(|err| Error::Status(format!("Couldn't derive token key: {err}")))?;
+
+        Ok(chacha20poly1305::ChaCha20Poly1305::new(&key.into()))
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl<S: TokenStore> TokenStore for EncryptedTokenStore<S> {
+    fn load(&self, account: &str) -> Result<Option<String>> {
+        use chacha20poly1305::aead::Aead;
+
+        let Some(encoded) = self.inner.load(account)? else {
+            return Ok(None);
+        };
+
+        let invalid = || Error::Status(format!("Couldn't decrypt token for {account}"));
+
+        let data = decode_hex(&encoded).ok_or_else(invalid)?;
+        if data.len() < Self::SALT_LENGTH + Self::NONCE_LENGTH {
+            return Err(invalid());
+        }
+
+        let (salt, rest) = data.split_at(Self::SALT_LENGTH);
+        let (nonce, ciphertext) = rest.split_at(Self::NONCE_LENGTH);
+        let token = self
+            .cipher(salt)?
+            .decrypt(nonce.into(), ciphertext)
+            .map_err(|_| invalid())?;
+
+        String::from_utf8(token).map(Some).map_err(|_| invalid())
+    }
+
+    fn store(&self, account: &str, token: &str) -> Result<()> {
+        use chacha20poly1305::aead::Aead;
+        use rand::RngCore;
+
+        let mut data = vec![0u8; Self::SALT_LENGTH + Self::NONCE_LENGTH];
+        rand::thread_rng().fill_bytes(&mut data);
+
+        let (salt, nonce) = data.split_at(Self::SALT_LENGTH);
+        let ciphertext = self
+            .cipher(salt)?
+            .encrypt(nonce.into(), token.as_bytes())
+            .map_err(|_| Error::Status(format!("Couldn't encrypt token for {account}")))?;
+        data.extend(ciphertext);
+
+        let encoded: String = data.iter().map(|b| format!("{b:02x}")).collect();
+        self.inner.store(account, &encoded)
+    }
+
+    fn remove(&self, account: &str) -> Result<()> {
+        self.inner.remove(account)
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    /* An odd length fails on the last, incomplete byte */
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store(name: &str) -> FileTokenStore {
+        let dir = std::env::temp_dir().join(format!("nextory-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        FileTokenStore::new(dir)
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let store = temp_store("file");
+        assert_eq!(store.load("default").unwrap(), None);
+
+        store.store("default", "token").unwrap();
+        assert_eq!(store.load("default").unwrap().as_deref(), Some("token"));
+
+        store.remove("default").unwrap();
+        assert_eq!(store.load("default").unwrap(), None);
+
+        let _ = fs::remove_dir_all(&store.dir);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypted_round_trip() {
+        let dir = temp_store("encrypted").dir;
+        let store = EncryptedTokenStore::new(FileTokenStore::new(dir.clone()), "secret".into());
+        store.store("default", "token").unwrap();
+
+        assert_eq!(store.load("default").unwrap().as_deref(), Some("token"));
+
+        let plain = FileTokenStore::new(dir.clone())
+            .load("default")
+            .unwrap()
+            .unwrap();
+        assert!(plain.chars().all(|c| c.is_ascii_hexdigit()));
+
+        let wrong = EncryptedTokenStore::new(FileTokenStore::new(dir.clone()), "wrong".into());
+        assert!(wrong.load("default").is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn plaintext_to_encrypted() {
+        let dir = temp_store("plaintext").dir;
+        FileTokenStore::new(dir.clone())
+            .store("default", "token")
+            .unwrap();
+
+        let store = EncryptedTokenStore::new(FileTokenStore::new(dir.clone()), "secret".into());
+        assert!(store.load("default").is_err());
+        assert_eq!(store.load_or_reset("default").unwrap(), None);
+        assert_eq!(store.load("default").unwrap(), None);
+
+        store.store("default", "new").unwrap();
+        assert_eq!(store.load_or_reset("default").unwrap().as_deref(), Some("new"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
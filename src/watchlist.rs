@@ -0,0 +1,108 @@
+/**
+ * Nextory Client
+ * Copyright (C) 2023 Luis
+ * 
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ * 
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use crate::{
+    api::{Error, Result},
+    common::{write_atomic, DateTime},
+};
+
+/**
+ * Books that couldn't be activated yet because they are upcoming, persisted as JSON
+ * so later runs can pick them up once they are released.
+ */
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug)]
+pub struct Watchlist {
+    #[serde(skip)]
+    path: PathBuf,
+    books: BTreeMap<u32, Upcoming>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Upcoming {
+    pub title: String,
+    pub authors: Box<[String]>,
+    pub pubdate: Option<DateTime>,
+    #[serde(default)]
+    pub esalesticket: String,
+}
+
+impl std::fmt::Display for Upcoming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.title)?;
+        if !self.authors.is_empty() {
+            f.write_fmt(format_args!(" by {}", self.authors.join(", ")))?;
+        }
+        match self.pubdate {
+            Some(pubdate) => f.write_fmt(format_args!(" ({})", pubdate.date_naive())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Watchlist {
+    /**
+     * Loads the watchlist from `path`, starting out empty if it doesn't exist yet.
+     */
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut watchlist: Watchlist = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|err| Error::Status(format!("Invalid watchlist {path:?}: {err}")))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Watchlist::default(),
+            Err(err) => return Err(err.into()),
+        };
+        watchlist.path = path;
+
+        Ok(watchlist)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let content = serde_json::to_vec_pretty(self)
+            .map_err(|err| Error::Status(format!("Couldn't serialize watchlist: {err}")))?;
+        write_atomic(&self.path, &content)?;
+
+        Ok(())
+    }
+
+    /**
+     * Returns true if the book wasn't watched before.
+     */
+    pub fn insert(&mut self, id: u32, book: Upcoming) -> bool {
+        self.books.insert(id, book).is_none()
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<Upcoming> {
+        self.books.remove(&id)
+    }
+
+    /**
+     * Books whose publication date has passed, or that never had one.
+     */
+    pub fn due(&self, now: DateTime) -> Vec<(u32, Upcoming)> {
+        self.books
+            .iter()
+            .filter(|(_, book)| book.pubdate.is_none_or(|pubdate| pubdate <= now))
+            .map(|(&id, book)| (id, book.clone()))
+            .collect()
+    }
+}